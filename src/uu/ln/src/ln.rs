@@ -12,8 +12,9 @@ extern crate uucore;
 
 extern crate clap;
 
+use std::env;
 use std::fs;
-use std::io::{stdin, Result};
+use std::io::{stdin, ErrorKind, Result};
 #[cfg(any(unix, target_os = "redox"))]
 use std::os::unix::fs::symlink;
 #[cfg(windows)]
@@ -47,18 +48,21 @@ const OPT_TARGET_DIRECTORY: &str = "target-directory";
 const OPT_NO_TARGET_DIRECTORY: &str = "no-target-directory";
 const OPT_VERBOSE: &str = "verbose";
 
+const OPT_RELATIVE: &str = "relative";
+const OPT_NO_DEREFERENCE: &str = "no-dereference";
+
 //TODO not implemented
 //TODO const OPT_DIRECTORY: &str = "directory";
 //TODO const OPT_LOGICAL: &str = "logical";
-//TODO const OPT_NO_DEREFERENCE: &str = "no-dereference";
 //TODO const OPT_PHYSICAL: &str = "physical";
-//TODO const OPT_RELATIVE: &str = "relative";
 
 pub struct Settings {
     overwrite: OverwriteMode,
     backup: BackupMode,
     suffix: String,
     symbolic: bool,
+    relative: bool,
+    no_dereference: bool,
     target_dir: Option<String>,
     no_target_dir: bool,
     verbose: bool,
@@ -80,6 +84,29 @@ pub enum BackupMode {
 }
 
 
+// Maps a `--backup` argument or a `VERSION_CONTROL` value to a
+// `BackupMode`. Both accept the same set of spellings.
+fn backup_mode_from_string(s: &str) -> Option<BackupMode> {
+    match s {
+        "simple" | "never" => Some(BackupMode::SimpleBackup),
+        "numbered" | "t" => Some(BackupMode::NumberedBackup),
+        "existing" | "nil" => Some(BackupMode::ExistingBackup),
+        "none" | "off" => Some(BackupMode::NoBackup),
+        _ => None,
+    }
+}
+
+// Resolves the backup method to use when `--backup`/`-b` is given without
+// an explicit argument: VERSION_CONTROL if set, otherwise "existing" like
+// GNU. Returns the offending value as Err if VERSION_CONTROL is set but
+// not one of the recognized spellings.
+fn backup_mode_from_version_control_env() -> std::result::Result<BackupMode, String> {
+    match env::var("VERSION_CONTROL") {
+        Ok(s) => backup_mode_from_string(&s).ok_or(s),
+        Err(_) => Ok(BackupMode::ExistingBackup),
+    }
+}
+
 fn get_usage() -> String {
     format!(
         "[OPTION]... [-T] TARGET LINK_NAME   (1st form)
@@ -104,9 +131,12 @@ pub fn uumain(args: Vec<String>) -> i32 {
         .arg(Arg::with_name(OPT_BACKUP)
             .long(OPT_BACKUP)
             .takes_value(true)
-            .possible_values(&["simple","never", "numbered","t", "existing","nil", "none"])
+            .min_values(0)
+            .require_equals(true)
+            .possible_values(&["simple","never", "numbered","t", "existing","nil", "none", "off"])
             .help("make a backup of each file that would otherwise be \
-                   overwritten or removed"))
+                   overwritten or removed. Without an argument, the \
+                   VERSION_CONTROL environment variable is used as the method"))
         //TODO:
         // .arg(Arg::with_name(OPT_DIRECTORY)
         //     .short("d")
@@ -127,23 +157,21 @@ pub fn uumain(args: Vec<String>) -> i32 {
         //  .long(OPT_LOGICAL)
         //  .help("dereference TARGETs that are symbolic links"))
         //
-        //TODO 
-        //.arg(Arg::with_name(OPT_NO_DEREFERENCE)
-        //  .short("n")
-        //  .long(OPT_NO_DEREFERENCE)
-        //  .help("treat LINK_NAME as a normal file if it is a symbolic link to a directory"))
+        .arg(Arg::with_name(OPT_NO_DEREFERENCE)
+            .short("n")
+            .long(OPT_NO_DEREFERENCE)
+            .help("treat LINK_NAME as a normal file if it is a symbolic link to a directory"))
         //
-        //TODO 
+        //TODO
         //.arg(Arg::with_name(OPT_PHYSICAL)
         //  .short("P") 
         //  .long(OPT_PHYSICAL) 
         //  .help("make hard links directly to symbolic links"))
         //
-        //TODO 
-        //  .arg(Arg::with_name(OPT_RELATIVE)
-        //  .short("r")
-        //  .long(OPT_RELATIVE)
-        //  .help("create symbolic links relative to link location"))
+        .arg(Arg::with_name(OPT_RELATIVE)
+            .short("r")
+            .long(OPT_RELATIVE)
+            .help("create symbolic links relative to link location"))
         .arg(Arg::with_name(OPT_SYMBOLIC)
             .short("s")
             .long(OPT_SYMBOLIC)
@@ -152,7 +180,6 @@ pub fn uumain(args: Vec<String>) -> i32 {
             .short("S")
             .long(OPT_SUFFIX)
             .takes_value(true)
-            .default_value("~")
             .help("override the usual backup suffix"))
         .arg(Arg::with_name(OPT_TARGET_DIRECTORY)
             .short("t")
@@ -182,32 +209,62 @@ pub fn uumain(args: Vec<String>) -> i32 {
     };
 
     let backup_mode = if matches.is_present(OPT_BACKUP) {
-        match matches.value_of(OPT_BACKUP).unwrap(){
-            "simple" | "never" => BackupMode::SimpleBackup,
-            "numbered" | "t" => BackupMode::NumberedBackup,
-            "existing" | "nil" => BackupMode::ExistingBackup,
-            "none" | "off" => BackupMode::NoBackup,
-            x => {
+        match matches.value_of(OPT_BACKUP) {
+            Some(x) => match backup_mode_from_string(x) {
+                Some(mode) => mode,
+                None => {
+                    show_error!(
+                        "invalid argument '{}' for 'backup type'\n\
+                         Try '{} --help' for more information.",
+                        x, NAME
+                    );
+                    return 1;
+                }
+            },
+            // --backup given without an argument: fall back to VERSION_CONTROL.
+            None => match backup_mode_from_version_control_env() {
+                Ok(mode) => mode,
+                Err(s) => {
+                    show_error!(
+                        "invalid argument '{}' for 'backup type'\n\
+                         Try '{} --help' for more information.",
+                        s, NAME
+                    );
+                    return 1;
+                }
+            },
+        }
+    } else if matches.is_present(OPT_BACKUP_NO_ARGS) {
+        // -b behaves like --backup given without an argument.
+        match backup_mode_from_version_control_env() {
+            Ok(mode) => mode,
+            Err(s) => {
                 show_error!(
-                    "invalid argument '{}' for 'backup method'\n\
+                    "invalid argument '{}' for 'backup type'\n\
                      Try '{} --help' for more information.",
-                    NAME,x
+                    s, NAME
                 );
                 return 1;
             }
         }
-    } else if matches.is_present(OPT_BACKUP_NO_ARGS) {
-        BackupMode::ExistingBackup
     }
     else {
         BackupMode::NoBackup
     };
-    
+
+    let suffix = matches
+        .value_of(OPT_SUFFIX)
+        .map(ToString::to_string)
+        .or_else(|| env::var("SIMPLE_BACKUP_SUFFIX").ok())
+        .unwrap_or_else(|| "~".to_owned());
+
     let settings = Settings {
         overwrite: overwrite_mode,
         backup: backup_mode,
-        suffix: matches.value_of(OPT_SUFFIX).unwrap().to_string(),
+        suffix,
         symbolic: matches.is_present(OPT_SYMBOLIC),
+        relative: matches.is_present(OPT_RELATIVE),
+        no_dereference: matches.is_present(OPT_NO_DEREFERENCE),
         target_dir: matches.value_of(OPT_TARGET_DIRECTORY).map(ToString::to_string),
         no_target_dir: matches.is_present(OPT_NO_TARGET_DIRECTORY),
         verbose: matches.is_present(OPT_VERBOSE),
@@ -241,7 +298,9 @@ fn exec(files: &[PathBuf], settings: &Settings) -> i32 {
             return link_files_in_dir(files, &PathBuf::from("."), &settings);
         }
         let last_file = &PathBuf::from(files.last().unwrap());
-        if files.len() > 2 || last_file.is_dir() {
+        let last_file_is_dir = last_file.is_dir()
+            && !(settings.no_dereference && is_symlink(last_file));
+        if files.len() > 2 || last_file_is_dir {
             // 3rd form: create links in the last argument.
             return link_files_in_dir(&files[0..files.len() - 1], last_file, &settings);
         }
@@ -267,7 +326,12 @@ fn exec(files: &[PathBuf], settings: &Settings) -> i32 {
     assert!(!files.is_empty());
 
     match link(&files[0], &files[1], settings) {
-        Ok(_) => 0,
+        Ok(outcome) => {
+            if settings.verbose {
+                print_link_outcome(&files[1], &outcome);
+            }
+            0
+        }
         Err(e) => {
             show_error!("{}", e);
             1
@@ -275,6 +339,17 @@ fn exec(files: &[PathBuf], settings: &Settings) -> i32 {
     }
 }
 
+// The outcome of a single `link()` call, kept around so callers that
+// link several files can print a consolidated report afterwards instead
+// of interleaving messages with prompts as they happen.
+enum LinkOutcome {
+    Linked {
+        source: PathBuf,
+        backup: Option<PathBuf>,
+    },
+    SkippedInteractive,
+}
+
 fn link_files_in_dir(files: &[PathBuf], target_dir: &PathBuf, settings: &Settings) -> i32 {
     if !target_dir.is_dir() {
         show_error!("target '{}' is not a directory", target_dir.display());
@@ -282,6 +357,7 @@ fn link_files_in_dir(files: &[PathBuf], target_dir: &PathBuf, settings: &Setting
     }
 
     let mut all_successful = true;
+    let mut results = Vec::with_capacity(files.len());
     for srcpath in files.iter() {
         let targetpath = match srcpath.as_os_str().to_str() {
             Some(name) => {
@@ -304,16 +380,29 @@ fn link_files_in_dir(files: &[PathBuf], target_dir: &PathBuf, settings: &Setting
             }
         };
 
-        if let Err(e) = link(srcpath, &targetpath, settings) {
-            show_error!(
+        let outcome = link(srcpath, &targetpath, settings);
+        if outcome.is_err() {
+            all_successful = false;
+        }
+        results.push((srcpath, targetpath, outcome));
+    }
+
+    for (srcpath, targetpath, outcome) in &results {
+        match outcome {
+            Ok(outcome) => {
+                if settings.verbose {
+                    print_link_outcome(targetpath, outcome);
+                }
+            }
+            Err(e) => show_error!(
                 "cannot link '{}' to '{}': {}",
                 targetpath.display(),
                 srcpath.display(),
                 e
-            );
-            all_successful = false;
+            ),
         }
     }
+
     if all_successful {
         0
     } else {
@@ -321,16 +410,37 @@ fn link_files_in_dir(files: &[PathBuf], target_dir: &PathBuf, settings: &Setting
     }
 }
 
-fn link(src: &PathBuf, dst: &PathBuf, settings: &Settings) -> Result<()> {
+fn print_link_outcome(dst: &PathBuf, outcome: &LinkOutcome) {
+    match outcome {
+        LinkOutcome::Linked { source, backup } => {
+            print!("'{}' -> '{}'", dst.display(), source.display());
+            match backup {
+                Some(path) => println!(" (backup: '{}')", path.display()),
+                None => println!(),
+            }
+        }
+        LinkOutcome::SkippedInteractive => {
+            println!("'{}' not replaced", dst.display());
+        }
+    }
+}
+
+fn link(src: &PathBuf, dst: &PathBuf, settings: &Settings) -> Result<LinkOutcome> {
     let mut backup_path = None;
 
+    let source: PathBuf = if settings.symbolic && settings.relative {
+        relative_path(src, dst)
+    } else {
+        src.clone()
+    };
+
     if is_symlink(dst) || dst.exists() {
         match settings.overwrite {
             OverwriteMode::NoClobber => {}
             OverwriteMode::Interactive => {
                 print!("{}: overwrite '{}'? ", NAME, dst.display());
                 if !read_yes() {
-                    return Ok(());
+                    return Ok(LinkOutcome::SkippedInteractive);
                 }
                 fs::remove_file(dst)?
             }
@@ -340,8 +450,8 @@ fn link(src: &PathBuf, dst: &PathBuf, settings: &Settings) -> Result<()> {
         backup_path = match settings.backup {
             BackupMode::NoBackup => None,
             BackupMode::SimpleBackup => Some(simple_backup_path(dst, &settings.suffix)),
-            BackupMode::NumberedBackup => Some(numbered_backup_path(dst)),
-            BackupMode::ExistingBackup => Some(existing_backup_path(dst, &settings.suffix)),
+            BackupMode::NumberedBackup => Some(numbered_backup_path(dst)?),
+            BackupMode::ExistingBackup => Some(existing_backup_path(dst, &settings.suffix)?),
         };
         if let Some(ref p) = backup_path {
             fs::rename(dst, p)?;
@@ -349,19 +459,15 @@ fn link(src: &PathBuf, dst: &PathBuf, settings: &Settings) -> Result<()> {
     }
 
     if settings.symbolic {
-        symlink(src, dst)?;
+        symlink(&source, dst)?;
     } else {
-        fs::hard_link(src, dst)?;
+        fs::hard_link(&source, dst)?;
     }
 
-    if settings.verbose {
-        print!("'{}' -> '{}'", dst.display(), src.display());
-        match backup_path {
-            Some(path) => println!(" (backup: '{}')", path.display()),
-            None => println!(),
-        }
-    }
-    Ok(())
+    Ok(LinkOutcome::Linked {
+        source,
+        backup: backup_path,
+    })
 }
 
 fn read_yes() -> bool {
@@ -375,29 +481,123 @@ fn read_yes() -> bool {
     }
 }
 
+// Rewrite `src` as a path relative to the directory that will contain
+// `dst`, so that the resulting symlink keeps working if the whole tree
+// is moved elsewhere. Falls back to `src` unchanged if no relative form
+// can be computed.
+fn relative_path(src: &PathBuf, dst: &PathBuf) -> PathBuf {
+    let abs_src = match canonicalize_missing(src) {
+        Some(p) => p,
+        None => return src.clone(),
+    };
+    let dst_dir = dst.parent().unwrap_or_else(|| Path::new("."));
+    let dst_dir = if dst_dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dst_dir
+    };
+    let abs_dst_dir = match canonicalize_missing(&dst_dir.to_path_buf()) {
+        Some(p) => p,
+        None => return src.clone(),
+    };
+
+    let src_components: Vec<_> = abs_src.components().collect();
+    let dst_components: Vec<_> = abs_dst_dir.components().collect();
+
+    let common = src_components
+        .iter()
+        .zip(dst_components.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut result = PathBuf::new();
+    for _ in common..dst_components.len() {
+        result.push("..");
+    }
+    for component in &src_components[common..] {
+        result.push(component.as_os_str());
+    }
+
+    if result.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        result
+    }
+}
+
+// Like `fs::canonicalize`, but tolerates a path whose final components
+// don't exist yet: it canonicalizes the longest existing ancestor and
+// re-appends the missing tail.
+fn canonicalize_missing(path: &PathBuf) -> Option<PathBuf> {
+    let mut missing = Vec::new();
+    let mut current = path.clone();
+
+    loop {
+        match current.canonicalize() {
+            Ok(mut found) => {
+                for component in missing.iter().rev() {
+                    found.push(component);
+                }
+                return Some(found);
+            }
+            Err(_) => {
+                let file_name = current.file_name()?.to_owned();
+                missing.push(file_name);
+                if !current.pop() {
+                    return None;
+                }
+                if current.as_os_str().is_empty() {
+                    current = PathBuf::from(".");
+                }
+            }
+        }
+    }
+}
+
 fn simple_backup_path(path: &PathBuf, suffix: &str) -> PathBuf {
     let mut p = path.as_os_str().to_str().unwrap().to_owned();
     p.push_str(suffix);
     PathBuf::from(p)
 }
 
-fn numbered_backup_path(path: &PathBuf) -> PathBuf {
+// Atomically claims `path` as a backup destination: creates it exclusively
+// so that a concurrent `ln` cannot pick the same name out from under us.
+// The caller still owns the reserved (now-existing, empty) file and is
+// expected to immediately `fs::rename` the real backup onto it.
+fn reserve_backup_path(path: &Path) -> Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map(|_| ())
+}
+
+fn numbered_backup_path(path: &PathBuf) -> Result<PathBuf> {
     let mut i: u64 = 1;
     loop {
-        let new_path = simple_backup_path(path, &format!(".~{}~", i));
-        if !new_path.exists() {
-            return new_path;
+        let candidate = simple_backup_path(path, &format!(".~{}~", i));
+        match reserve_backup_path(&candidate) {
+            Ok(()) => return Ok(candidate),
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => i += 1,
+            Err(e) => return Err(e),
         }
-        i += 1;
     }
 }
 
-fn existing_backup_path(path: &PathBuf, suffix: &str) -> PathBuf {
+fn existing_backup_path(path: &PathBuf, suffix: &str) -> Result<PathBuf> {
     let test_path = simple_backup_path(path, &".~1~".to_owned());
     if test_path.exists() {
         return numbered_backup_path(path);
     }
-    simple_backup_path(path, suffix)
+    let candidate = simple_backup_path(path, suffix);
+    match reserve_backup_path(&candidate) {
+        Ok(()) => Ok(candidate),
+        // The simple name is already taken (by an earlier backup or a
+        // concurrent `ln`); fall back to a numbered backup instead of
+        // overwriting it.
+        Err(ref e) if e.kind() == ErrorKind::AlreadyExists => numbered_backup_path(path),
+        Err(e) => Err(e),
+    }
 }
 
 #[cfg(windows)]